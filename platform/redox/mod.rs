@@ -0,0 +1,125 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The Redox OS sandboxing backend.
+//!
+//! Redox isolates processes through per-process scheme and namespace visibility rather than a
+//! Linux-style syscall filter, so containment is coarser-grained: most of the operations in
+//! `profile::Operation` can only be denied or allowed as a whole, not scoped to a specific path
+//! or address the way the Linux/macOS backends can.
+
+pub mod process;
+
+use profile;
+use profile::{Activate, AddressPattern, OperationSupport, OperationSupportLevel, PathPattern, Profile};
+use syscall;
+
+use std::path::Path;
+
+/// Redox-specific operations. There are none yet: everything expressible on Redox today is
+/// already covered by `profile::Operation`.
+#[derive(Clone, Debug)]
+pub enum Operation {}
+
+impl OperationSupport for Operation {
+    fn support(&self) -> OperationSupportLevel {
+        match *self {}
+    }
+}
+
+impl OperationSupport for profile::Operation {
+    fn support(&self) -> OperationSupportLevel {
+        match *self {
+            // Redox scopes filesystem visibility per-namespace, not per-path, so these can only
+            // be granted precisely when the pattern covers the whole filesystem -- anything more
+            // specific would grant access `file:` doesn't know how to narrow.
+            profile::Operation::FileReadAll(ref pattern)
+            | profile::Operation::FileReadMetadata(ref pattern) => file_pattern_support(pattern),
+            // Likewise, outbound network access is gated on visibility of the `tcp:`/`udp:`
+            // schemes as a whole, not on a per-address or per-port basis.
+            profile::Operation::NetworkOutbound(ref pattern) => address_pattern_support(pattern),
+            profile::Operation::SystemInfoRead => OperationSupportLevel::AlwaysAllowed,
+            profile::Operation::PlatformSpecific(ref op) => op.support(),
+        }
+    }
+}
+
+/// `FileReadAll`/`FileReadMetadata` can only be allowed precisely when `pattern` already asks for
+/// the entire filesystem; granting the `file:` scheme for anything narrower would allow more than
+/// the pattern requested, which is exactly what `CannotBeAllowedPrecisely` exists to flag (see
+/// `profile::Profile::new`, which refuses to construct a profile containing such an operation).
+fn file_pattern_support(pattern: &PathPattern) -> OperationSupportLevel {
+    match *pattern {
+        PathPattern::Subpath(ref path) if path.as_path() == Path::new("/") => {
+            OperationSupportLevel::CanBeAllowed
+        }
+        _ => OperationSupportLevel::CannotBeAllowedPrecisely,
+    }
+}
+
+/// `NetworkOutbound` can only be allowed precisely when `pattern` is `AddressPattern::All`, for
+/// the same reason as `file_pattern_support`: the `tcp:`/`udp:` schemes are all-or-nothing, so a
+/// pattern scoped to one port or socket can't be granted without also granting the rest.
+fn address_pattern_support(pattern: &AddressPattern) -> OperationSupportLevel {
+    match *pattern {
+        AddressPattern::All => OperationSupportLevel::CanBeAllowed,
+        _ => OperationSupportLevel::CannotBeAllowedPrecisely,
+    }
+}
+
+impl Activate for Profile {
+    fn activate(&self) -> Result<(), ()> {
+        // Redox has no syscall-filtering facility comparable to seccomp; containment instead
+        // comes from restricting which schemes (`file:`, `tcp:`, ...) this process's namespace
+        // can see. `mkns` creates a new namespace containing only the given schemes, and
+        // `setrens` then moves the calling process into it -- there is no way back to the wider
+        // namespace, which matches the "forevermore" contract `Activate::activate` documents.
+        let schemes = allowed_schemes(self);
+        // `mkns` takes scheme names as `(ptr, len)` pairs rather than `&str`/`&[u8]` directly, so
+        // that it can be called without depending on `core::str`; build that representation from
+        // the owned `String`s, which `schemes` keeps alive for the duration of the call.
+        let raw_schemes: Vec<[usize; 2]> = schemes
+            .iter()
+            .map(|scheme| {
+                let bytes = scheme.as_bytes();
+                [bytes.as_ptr() as usize, bytes.len()]
+            })
+            .collect();
+        let namespace = syscall::call::mkns(&raw_schemes).map_err(|_| ())?;
+        syscall::call::setrens(namespace, namespace).map(|_| ()).map_err(|_| ())
+    }
+}
+
+/// The set of Redox schemes that must stay visible for `profile`'s allowed operations to work,
+/// e.g. `FileReadAll`/`FileReadMetadata` need the `file:` scheme. Operations this backend cannot
+/// scope any more tightly than "the whole scheme" are never present in `profile.allowed_operations()`
+/// -- `Profile::new` refuses to construct a profile containing one (see `OperationSupport` above)
+/// -- so only the patterns that already ask for everything reach this match.
+///
+/// `sys:` is listed unconditionally: `SystemInfoRead` is `AlwaysAllowed`, so `Profile::new` never
+/// lets it appear in `allowed_operations()` either, yet the namespace still needs to expose it.
+fn allowed_schemes(profile: &Profile) -> Vec<String> {
+    let mut schemes = vec!["sys".to_owned()];
+    for operation in profile.allowed_operations() {
+        match *operation {
+            profile::Operation::FileReadAll(_) | profile::Operation::FileReadMetadata(_) => {
+                schemes.push("file".to_owned());
+            }
+            profile::Operation::NetworkOutbound(_) => {
+                schemes.push("tcp".to_owned());
+                schemes.push("udp".to_owned());
+            }
+            profile::Operation::SystemInfoRead | profile::Operation::PlatformSpecific(_) => {}
+        }
+    }
+    schemes.sort();
+    schemes.dedup();
+    schemes
+}
@@ -0,0 +1,412 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Child process management on Redox.
+//!
+//! Redox's C library, relibc, implements enough of the POSIX process API (`fork`, `execve`,
+//! `pipe`, `waitpid`) to reuse the same fork-and-exec strategy as the other Unix-like backends.
+//! `posix_spawn` support in relibc is not yet complete enough to rely on, so unlike
+//! `platform::unix::process`, this module always uses `fork`/`exec`.
+
+use sandbox::{Command, Stdio};
+
+use libc::dup2;
+use libc::pipe;
+use libc::{self, c_char, c_int};
+use libc::{execve, fork, kill, pid_t, waitpid, SIGKILL, WEXITSTATUS, WIFEXITED, WNOHANG, WTERMSIG};
+use libc::{FD_CLOEXEC, F_SETFD};
+use libc::{O_RDONLY, O_WRONLY};
+use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+/// A trailing marker written after the 4-byte errno so that a short or garbage read on the
+/// error pipe can't be mistaken for a real (if oddly small) errno payload.
+const EXEC_FAILURE_SENTINEL: &[u8; 4] = b"NOEX";
+
+/// Creates a pipe whose file descriptors are marked close-on-exec, so that a successful
+/// `execve` in the child closes it implicitly and the parent can tell exec succeeded just by
+/// seeing end-of-file.
+unsafe fn cloexec_pipe() -> io::Result<[c_int; 2]> {
+    let mut fds = [0 as c_int; 2];
+    if pipe(&mut fds[0]) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in &fds {
+        if libc::fcntl(fd, F_SETFD, FD_CLOEXEC) < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            return Err(err);
+        }
+    }
+    Ok(fds)
+}
+
+/// The concrete file descriptors a `Stdio` setting resolves to for one of the child's standard
+/// streams.
+struct PreparedStdio {
+    /// The fd to `dup2` onto the child's standard stream, or `None` to leave the parent's fd
+    /// alone (`Stdio::Inherit`).
+    child_fd: Option<RawFd>,
+    /// An fd this call opened (a pipe end, or `/dev/null`) that must be closed in the parent
+    /// once the child has its own copy of it.
+    close_in_parent: Option<RawFd>,
+    /// The parent-side end of a pipe, handed back as `Process::stdin`/`stdout`/`stderr` when the
+    /// setting was `Stdio::Piped`.
+    parent_file: Option<File>,
+}
+
+impl PreparedStdio {
+    fn fd(fd: RawFd) -> PreparedStdio {
+        PreparedStdio {
+            child_fd: Some(fd),
+            close_in_parent: None,
+            parent_file: None,
+        }
+    }
+}
+
+/// Resolves `stdio` into concrete file descriptors. `readable` is `true` when the child reads
+/// from this stream (stdin) and `false` when the child writes to it (stdout/stderr).
+fn prepare_stdio(stdio: &Stdio, readable: bool) -> io::Result<PreparedStdio> {
+    match *stdio {
+        Stdio::Inherit => Ok(PreparedStdio {
+            child_fd: None,
+            close_in_parent: None,
+            parent_file: None,
+        }),
+        Stdio::Null => {
+            let flags = if readable { O_RDONLY } else { O_WRONLY };
+            let path = CString::new(&b"/dev/null"[..]).unwrap();
+            let fd = unsafe { libc::open(path.as_ptr(), flags) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(PreparedStdio {
+                child_fd: Some(fd),
+                close_in_parent: Some(fd),
+                parent_file: None,
+            })
+        }
+        Stdio::Piped => {
+            let mut fds = [0 as c_int; 2];
+            if unsafe { pipe(&mut fds[0]) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let (child_end, parent_end) = if readable {
+                (fds[0], fds[1])
+            } else {
+                (fds[1], fds[0])
+            };
+            Ok(PreparedStdio {
+                child_fd: Some(child_end),
+                close_in_parent: Some(child_end),
+                parent_file: Some(unsafe { File::from_raw_fd(parent_end) }),
+            })
+        }
+        Stdio::Fd(fd) => Ok(PreparedStdio::fd(fd)),
+        Stdio::File(ref file) => Ok(PreparedStdio::fd(file.as_raw_fd())),
+    }
+}
+
+/// Closes `prepared`'s `close_in_parent` fd, if any. Used to unwind a `prepare_stdio` call that
+/// already succeeded once a sibling stream's preparation fails, so the earlier fd (a pipe end,
+/// or `/dev/null`) isn't leaked in the parent.
+unsafe fn close_prepared(prepared: &PreparedStdio) {
+    if let Some(fd) = prepared.close_in_parent {
+        libc::close(fd);
+    }
+}
+
+/// Closes every `close_in_parent` fd across `stdin`/`stdout`/`stderr`.
+unsafe fn close_in_parent(stdin: &PreparedStdio, stdout: &PreparedStdio, stderr: &PreparedStdio) {
+    for prepared in &[stdin, stdout, stderr] {
+        close_prepared(prepared);
+    }
+}
+
+/// Applies `prepared` to the child's `target` standard stream (`STDIN_FILENO` and friends):
+/// closes the now-unneeded parent-side pipe end, if any, then `dup2`s the child-side fd into
+/// place.
+unsafe fn apply_child_stdio(prepared: &PreparedStdio, target: c_int) {
+    if let Some(ref file) = prepared.parent_file {
+        libc::close(file.as_raw_fd());
+    }
+    if let Some(fd) = prepared.child_fd {
+        if fd != target {
+            assert_eq!(dup2(fd, target), target);
+            libc::close(fd);
+        }
+    }
+}
+
+/// Builds a `CString` from raw bytes, turning an embedded NUL byte into a recoverable
+/// `io::Error` rather than the panic `CString::new(..).unwrap()` would give.
+fn cstring(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Builds `argv`, with `command.module_path` as `argv[0]`.
+fn build_argv(command: &Command) -> io::Result<Vec<CString>> {
+    let mut argv = Vec::with_capacity(command.args.len() + 1);
+    argv.push(cstring(&command.module_path)?);
+    for arg in command.args.iter() {
+        argv.push(cstring(arg)?);
+    }
+    Ok(argv)
+}
+
+fn build_envp(command: &Command) -> io::Result<Vec<CString>> {
+    command
+        .env
+        .iter()
+        .map(|(key, value)| {
+            let mut entry = Vec::with_capacity(key.len() + value.len() + 1);
+            entry.extend_from_slice(key);
+            entry.push(b'=');
+            entry.extend_from_slice(value);
+            cstring(&entry)
+        })
+        .collect()
+}
+
+/// Turns a list of `CString`s into a NUL-terminated array of pointers suitable for `argv`/`envp`.
+fn c_string_ptrs(strings: &[CString]) -> Vec<*const c_char> {
+    let mut ptrs: Vec<_> = strings.iter().map(|entry| entry.as_ptr()).collect();
+    ptrs.push(ptr::null());
+    ptrs
+}
+
+pub fn exec(command: &Command) -> io::Error {
+    let argv = match build_argv(command) {
+        Ok(argv) => argv,
+        Err(err) => return err,
+    };
+    let envp = match build_envp(command) {
+        Ok(envp) => envp,
+        Err(err) => return err,
+    };
+    let args = c_string_ptrs(&argv);
+    let env = c_string_ptrs(&envp);
+
+    unsafe {
+        execve(argv[0].as_ptr(), args.as_ptr(), env.as_ptr());
+    }
+
+    io::Error::last_os_error()
+}
+
+pub fn spawn(command: &Command) -> io::Result<Process> {
+    let stdin = prepare_stdio(&command.stdin, true)?;
+    let stdout = match prepare_stdio(&command.stdout, false) {
+        Ok(stdout) => stdout,
+        Err(err) => {
+            unsafe { close_prepared(&stdin) };
+            return Err(err);
+        }
+    };
+    let stderr = match prepare_stdio(&command.stderr, false) {
+        Ok(stderr) => stderr,
+        Err(err) => {
+            unsafe {
+                close_prepared(&stdin);
+                close_prepared(&stdout);
+            }
+            return Err(err);
+        }
+    };
+
+    let err_pipe = match unsafe { cloexec_pipe() } {
+        Ok(err_pipe) => err_pipe,
+        Err(err) => {
+            unsafe { close_in_parent(&stdin, &stdout, &stderr) };
+            return Err(err);
+        }
+    };
+
+    unsafe {
+        match fork() {
+            -1 => {
+                let err = io::Error::last_os_error();
+                libc::close(err_pipe[0]);
+                libc::close(err_pipe[1]);
+                close_in_parent(&stdin, &stdout, &stderr);
+                return Err(err);
+            }
+            0 => {
+                libc::close(err_pipe[0]);
+
+                apply_child_stdio(&stdin, STDIN_FILENO);
+                apply_child_stdio(&stdout, STDOUT_FILENO);
+                apply_child_stdio(&stderr, STDERR_FILENO);
+
+                let err = exec(command);
+                let errno = err.raw_os_error().unwrap_or(0) as i32;
+                let mut payload = [0u8; 8];
+                payload[..4].copy_from_slice(&errno.to_ne_bytes());
+                payload[4..].copy_from_slice(EXEC_FAILURE_SENTINEL);
+                libc::write(err_pipe[1], payload.as_ptr() as *const _, payload.len());
+                libc::_exit(127);
+            }
+            pid => {
+                libc::close(err_pipe[1]);
+                if let Some(fd) = stdin.close_in_parent {
+                    libc::close(fd);
+                }
+                if let Some(fd) = stdout.close_in_parent {
+                    libc::close(fd);
+                }
+                if let Some(fd) = stderr.close_in_parent {
+                    libc::close(fd);
+                }
+
+                let mut err_reader = File::from_raw_fd(err_pipe[0]);
+                let mut payload = [0u8; 8];
+                let mut read = 0;
+                loop {
+                    match err_reader.read(&mut payload[read..]) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            read += n;
+                            if read == payload.len() {
+                                break;
+                            }
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                if read == 0 {
+                    // `execve` succeeded and closed the close-on-exec write end for us.
+                    return Ok(Process {
+                        pid,
+                        stdin: stdin.parent_file,
+                        stdout: stdout.parent_file,
+                        stderr: stderr.parent_file,
+                    });
+                }
+
+                let mut stat = 0;
+                while waitpid(pid, &mut stat, 0) < 0
+                    && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+                {}
+
+                if read != payload.len() || &payload[4..] != EXEC_FAILURE_SENTINEL {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "child sent a short or malformed exec-failure payload",
+                    ));
+                }
+
+                let errno = i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                Err(io::Error::from_raw_os_error(errno))
+            }
+        }
+    }
+}
+
+#[allow(missing_copy_implementations)]
+pub struct Process {
+    pub pid: pid_t,
+    /// Present when `Command::stdin` was `Stdio::Piped`.
+    pub stdin: Option<File>,
+    /// Present when `Command::stdout` was `Stdio::Piped`.
+    pub stdout: Option<File>,
+    /// Present when `Command::stderr` was `Stdio::Piped`.
+    pub stderr: Option<File>,
+}
+
+impl Process {
+    /// Rejects a non-positive `pid`: `0` and negative values are process-group targets to
+    /// `waitpid`/`kill`, not this specific process, and must never reach the raw syscall.
+    fn check_pid(&self) -> io::Result<()> {
+        if self.pid <= 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Process has an invalid pid",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Blocks until this process (and only this process) exits.
+    pub fn wait(&self) -> io::Result<ExitStatus> {
+        self.check_pid()?;
+        let mut stat = 0;
+        loop {
+            match unsafe { waitpid(self.pid, &mut stat, 0) } {
+                pid if pid == self.pid => break,
+                _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+
+        unsafe {
+            if WIFEXITED(stat) {
+                Ok(ExitStatus::Code(WEXITSTATUS(stat) as i32))
+            } else {
+                Ok(ExitStatus::Signal(WTERMSIG(stat) as i32))
+            }
+        }
+    }
+
+    /// Checks whether this process has exited, without blocking. Returns `Ok(None)` if it is
+    /// still running.
+    pub fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        self.check_pid()?;
+        let mut stat = 0;
+        loop {
+            match unsafe { waitpid(self.pid, &mut stat, WNOHANG) } {
+                0 => return Ok(None),
+                pid if pid == self.pid => break,
+                _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+
+        unsafe {
+            if WIFEXITED(stat) {
+                Ok(Some(ExitStatus::Code(WEXITSTATUS(stat) as i32)))
+            } else {
+                Ok(Some(ExitStatus::Signal(WTERMSIG(stat) as i32)))
+            }
+        }
+    }
+
+    /// Forcibly terminates this process by sending it `SIGKILL`.
+    pub fn kill(&self) -> io::Result<()> {
+        self.check_pid()?;
+        if unsafe { kill(self.pid, SIGKILL) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+pub enum ExitStatus {
+    Code(i32),
+    Signal(i32),
+}
+
+impl ExitStatus {
+    #[inline]
+    pub fn success(&self) -> bool {
+        match *self {
+            ExitStatus::Code(0) => true,
+            _ => false,
+        }
+    }
+}
@@ -10,94 +10,439 @@
 
 //! Child process management on POSIX systems.
 
-use sandbox::Command;
+use sandbox::{Command, Stdio};
 
 use libc::dup2;
 use libc::pipe;
 use libc::{self, c_char, c_int};
-use libc::{execve, fork, pid_t, waitpid, WEXITSTATUS, WIFEXITED, WTERMSIG};
+use libc::{execve, fork, kill, pid_t, waitpid, SIGKILL, WEXITSTATUS, WIFEXITED, WNOHANG, WTERMSIG};
+use libc::{posix_spawn, posix_spawn_file_actions_t, posix_spawnattr_t};
+use libc::{sigset_t, O_CLOEXEC, O_RDONLY, O_WRONLY, POSIX_SPAWN_SETSIGDEF};
+use libc::{FD_CLOEXEC, F_SETFD};
 use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 use std::ffi::CString;
 use std::fs::File;
 use std::io;
-use std::os::unix::io::FromRawFd;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::ptr;
-use std::str;
 
-pub fn exec(command: &Command) -> io::Error {
-    let mut args: Vec<_> = vec![command.module_path.as_ptr()];
+/// A trailing marker written after the 4-byte errno so that a short or garbage read on the
+/// error pipe can't be mistaken for a real (if oddly small) errno payload.
+const EXEC_FAILURE_SENTINEL: &[u8; 4] = b"NOEX";
+
+/// Creates a pipe whose file descriptors are marked close-on-exec, so that a successful
+/// `execve` in the child closes it implicitly and the parent can tell exec succeeded just by
+/// seeing end-of-file.
+unsafe fn cloexec_pipe() -> io::Result<[c_int; 2]> {
+    let mut fds = [0 as c_int; 2];
+    if pipe(&mut fds[0]) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in &fds {
+        if libc::fcntl(fd, F_SETFD, FD_CLOEXEC) < 0 {
+            let err = io::Error::last_os_error();
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            return Err(err);
+        }
+    }
+    Ok(fds)
+}
+
+/// The concrete file descriptors a `Stdio` setting resolves to for one of the child's standard
+/// streams.
+struct PreparedStdio {
+    /// The fd to `dup2` onto the child's standard stream, or `None` to leave the parent's fd
+    /// alone (`Stdio::Inherit`).
+    child_fd: Option<RawFd>,
+    /// An fd this call opened (a pipe end, or `/dev/null`) that must be closed in the parent
+    /// once the child has its own copy of it.
+    close_in_parent: Option<RawFd>,
+    /// The parent-side end of a pipe, handed back as `Process::stdin`/`stdout`/`stderr` when the
+    /// setting was `Stdio::Piped`.
+    parent_file: Option<File>,
+}
+
+impl PreparedStdio {
+    fn fd(fd: RawFd) -> PreparedStdio {
+        PreparedStdio {
+            child_fd: Some(fd),
+            close_in_parent: None,
+            parent_file: None,
+        }
+    }
+}
+
+/// Resolves `stdio` into concrete file descriptors. `readable` is `true` when the child reads
+/// from this stream (stdin) and `false` when the child writes to it (stdout/stderr).
+///
+/// Every fd this function opens itself (a pipe end, or `/dev/null`) is marked close-on-exec.
+/// `dup2`/`posix_spawn_file_actions_adddup2` always clear `FD_CLOEXEC` on the *target* fd they
+/// produce, so the redirected stream survives `exec` while these original fds close themselves
+/// on `exec` in whichever process ends up not explicitly closing them first — which matters for
+/// the `posix_spawn` path in `try_posix_spawn`, where there is no per-child code between the
+/// `dup2`s and the `exec` to close them by hand.
+fn prepare_stdio(stdio: &Stdio, readable: bool) -> io::Result<PreparedStdio> {
+    match *stdio {
+        Stdio::Inherit => Ok(PreparedStdio {
+            child_fd: None,
+            close_in_parent: None,
+            parent_file: None,
+        }),
+        Stdio::Null => {
+            let flags = (if readable { O_RDONLY } else { O_WRONLY }) | O_CLOEXEC;
+            let path = CString::new(&b"/dev/null"[..]).unwrap();
+            let fd = unsafe { libc::open(path.as_ptr(), flags) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(PreparedStdio {
+                child_fd: Some(fd),
+                close_in_parent: Some(fd),
+                parent_file: None,
+            })
+        }
+        Stdio::Piped => {
+            let fds = unsafe { cloexec_pipe()? };
+            let (child_end, parent_end) = if readable {
+                (fds[0], fds[1])
+            } else {
+                (fds[1], fds[0])
+            };
+            Ok(PreparedStdio {
+                child_fd: Some(child_end),
+                close_in_parent: Some(child_end),
+                parent_file: Some(unsafe { File::from_raw_fd(parent_end) }),
+            })
+        }
+        Stdio::Fd(fd) => Ok(PreparedStdio::fd(fd)),
+        Stdio::File(ref file) => Ok(PreparedStdio::fd(file.as_raw_fd())),
+    }
+}
+
+/// Builds a `CString` from raw bytes, turning an embedded NUL byte into a recoverable
+/// `io::Error` rather than the panic `CString::new(..).unwrap()` would give.
+fn cstring(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Builds `argv`, with `command.module_path` as `argv[0]`.
+fn build_argv(command: &Command) -> io::Result<Vec<CString>> {
+    let mut argv = Vec::with_capacity(command.args.len() + 1);
+    argv.push(cstring(&command.module_path)?);
     for arg in command.args.iter() {
-        args.push(arg.as_ptr())
+        argv.push(cstring(arg)?);
     }
-    args.push(ptr::null());
+    Ok(argv)
+}
 
-    let env: Vec<_> = command
+fn build_envp(command: &Command) -> io::Result<Vec<CString>> {
+    command
         .env
         .iter()
         .map(|(key, value)| {
-            let entry = format!(
-                "{}={}",
-                str::from_utf8(key.to_bytes()).unwrap(),
-                str::from_utf8(value.to_bytes()).unwrap()
-            );
-            CString::new(entry).unwrap()
+            let mut entry = Vec::with_capacity(key.len() + value.len() + 1);
+            entry.extend_from_slice(key);
+            entry.push(b'=');
+            entry.extend_from_slice(value);
+            cstring(&entry)
         })
-        .collect();
-    let mut env: Vec<_> = env.iter().map(|entry| entry.as_ptr()).collect();
-    env.push(ptr::null());
+        .collect()
+}
+
+/// Turns a list of `CString`s into a NUL-terminated array of pointers suitable for `argv`/`envp`.
+fn c_string_ptrs(strings: &[CString]) -> Vec<*const c_char> {
+    let mut ptrs: Vec<_> = strings.iter().map(|entry| entry.as_ptr()).collect();
+    ptrs.push(ptr::null());
+    ptrs
+}
+
+pub fn exec(command: &Command) -> io::Error {
+    let argv = match build_argv(command) {
+        Ok(argv) => argv,
+        Err(err) => return err,
+    };
+    let envp = match build_envp(command) {
+        Ok(envp) => envp,
+        Err(err) => return err,
+    };
+    let args = c_string_ptrs(&argv);
+    let env = c_string_ptrs(&envp);
 
     unsafe {
-        execve(command.module_path.as_ptr(), args.as_ptr(), env.as_ptr());
+        execve(argv[0].as_ptr(), args.as_ptr(), env.as_ptr());
     }
 
     io::Error::last_os_error()
 }
 
-pub fn spawn(command: &Command) -> io::Result<Process> {
-    let mut fd1 = [0 as c_int; 2];
-    let mut fd2 = [0 as c_int; 2];
-    let mut fd3 = [0 as c_int; 2];
+/// Applies `prepared` to the child's `target` standard stream (`STDIN_FILENO` and friends) in
+/// the `fork`/`exec` path: closes the now-unneeded parent-side pipe end, if any, then `dup2`s
+/// the child-side fd into place.
+unsafe fn apply_child_stdio(prepared: &PreparedStdio, target: c_int) {
+    if let Some(ref file) = prepared.parent_file {
+        libc::close(file.as_raw_fd());
+    }
+    if let Some(fd) = prepared.child_fd {
+        if fd != target {
+            assert_eq!(dup2(fd, target), target);
+            libc::close(fd);
+        } else {
+            // No `dup2` ran to clear `FD_CLOEXEC`, so clear it here to keep the stream open
+            // across `exec`.
+            libc::fcntl(fd, F_SETFD, 0);
+        }
+    }
+}
 
-    if unsafe { pipe(&mut fd1[0]) } < 0 {
-        return Err(io::Error::last_os_error());
+/// Closes `prepared`'s `close_in_parent` fd, if any. Used to unwind a `prepare_stdio` call that
+/// already succeeded once a sibling stream's preparation fails, so the earlier fd (a pipe end,
+/// or `/dev/null`) isn't leaked in the parent.
+unsafe fn close_prepared(prepared: &PreparedStdio) {
+    if let Some(fd) = prepared.close_in_parent {
+        libc::close(fd);
     }
-    if unsafe { pipe(&mut fd2[0]) } < 0 {
-        return Err(io::Error::last_os_error());
+}
+
+/// Closes every `close_in_parent` fd across `stdin`/`stdout`/`stderr`. Used when `try_posix_spawn`
+/// must abandon an attempt after `prepare_stdio` already opened these fds (a pipe end, or
+/// `/dev/null`) but before `posix_spawn` ran to hand the child its own copy of them — otherwise
+/// every failed attempt (for example `ENOENT` on a missing sandboxee) leaks them in the parent.
+unsafe fn close_in_parent(stdin: &PreparedStdio, stdout: &PreparedStdio, stderr: &PreparedStdio) {
+    for prepared in &[stdin, stdout, stderr] {
+        close_prepared(prepared);
     }
-    if unsafe { pipe(&mut fd3[0]) } < 0 {
-        return Err(io::Error::last_os_error());
+}
+
+/// Whether `command` can be spawned through `posix_spawn`.
+///
+/// `posix_spawn` has no equivalent of running arbitrary code between `fork` and `exec`, so a
+/// command that needs a sandbox `Activate` step to run in the child before `execve` cannot use
+/// it and is handed to `spawn_fork_exec` instead. `spawn_fork_exec` itself runs no such step: it
+/// only wires up the child's standard streams before `execve`. Nothing in this tree sets
+/// `command.requires_child_activation`, so it is always `false` and `posix_spawn` is always
+/// tried first.
+fn can_use_posix_spawn(command: &Command) -> bool {
+    !command.requires_child_activation
+}
+
+/// What `try_posix_spawn` did with the prepared stdio: either it produced a final result, or
+/// `command` required something `posix_spawn` can't express and the prepared fds are handed
+/// back untouched for the `fork`/`exec` fallback.
+enum SpawnAttempt {
+    Done(io::Result<Process>),
+    Fallback(PreparedStdio, PreparedStdio, PreparedStdio),
+}
+
+/// Attempts to spawn `command` via `posix_spawn`, wiring up the prepared stdio. `posix_spawn` is
+/// far safer than `fork` in a multithreaded host, since (unlike the code between `fork` and
+/// `execve`) it never runs arbitrary non-async-signal-safe code in the child.
+fn try_posix_spawn(
+    command: &Command,
+    stdin: PreparedStdio,
+    stdout: PreparedStdio,
+    stderr: PreparedStdio,
+) -> SpawnAttempt {
+    if !can_use_posix_spawn(command) {
+        return SpawnAttempt::Fallback(stdin, stdout, stderr);
     }
 
+    let argv = match build_argv(command) {
+        Ok(argv) => argv,
+        Err(err) => {
+            unsafe { close_in_parent(&stdin, &stdout, &stderr) };
+            return SpawnAttempt::Done(Err(err));
+        }
+    };
+    let envp = match build_envp(command) {
+        Ok(envp) => envp,
+        Err(err) => {
+            unsafe { close_in_parent(&stdin, &stdout, &stderr) };
+            return SpawnAttempt::Done(Err(err));
+        }
+    };
+    let args = c_string_ptrs(&argv);
+    let env = c_string_ptrs(&envp);
+
     unsafe {
-        match fork() {
-            0 => {
-                libc::close(fd1[1]);
-                libc::close(fd2[0]);
-                libc::close(fd3[0]);
+        let mut file_actions: posix_spawn_file_actions_t = mem::zeroed();
+        if libc::posix_spawn_file_actions_init(&mut file_actions) != 0 {
+            let err = io::Error::last_os_error();
+            close_in_parent(&stdin, &stdout, &stderr);
+            return SpawnAttempt::Done(Err(err));
+        }
+        for &(prepared, target) in &[
+            (&stdin, STDIN_FILENO),
+            (&stdout, STDOUT_FILENO),
+            (&stderr, STDERR_FILENO),
+        ] {
+            if let Some(fd) = prepared.child_fd {
+                libc::posix_spawn_file_actions_adddup2(&mut file_actions, fd, target);
+            }
+        }
+
+        let mut attr: posix_spawnattr_t = mem::zeroed();
+        if libc::posix_spawnattr_init(&mut attr) != 0 {
+            let err = io::Error::last_os_error();
+            libc::posix_spawn_file_actions_destroy(&mut file_actions);
+            close_in_parent(&stdin, &stdout, &stderr);
+            return SpawnAttempt::Done(Err(err));
+        }
+        libc::posix_spawnattr_setflags(&mut attr, POSIX_SPAWN_SETSIGDEF as i16);
+        let mut all_signals: sigset_t = mem::zeroed();
+        libc::sigfillset(&mut all_signals);
+        libc::posix_spawnattr_setsigdefault(&mut attr, &all_signals);
+
+        let mut pid: pid_t = 0;
+        let ret = posix_spawn(
+            &mut pid,
+            argv[0].as_ptr(),
+            &file_actions,
+            &attr,
+            args.as_ptr() as *const *mut c_char,
+            env.as_ptr() as *const *mut c_char,
+        );
+
+        libc::posix_spawnattr_destroy(&mut attr);
+        libc::posix_spawn_file_actions_destroy(&mut file_actions);
+
+        if ret != 0 {
+            close_in_parent(&stdin, &stdout, &stderr);
+            return SpawnAttempt::Done(Err(io::Error::from_raw_os_error(ret)));
+        }
+
+        close_in_parent(&stdin, &stdout, &stderr);
+
+        SpawnAttempt::Done(Ok(Process {
+            pid,
+            stdin: stdin.parent_file,
+            stdout: stdout.parent_file,
+            stderr: stderr.parent_file,
+        }))
+    }
+}
+
+pub fn spawn(command: &Command) -> io::Result<Process> {
+    let stdin = prepare_stdio(&command.stdin, true)?;
+    let stdout = match prepare_stdio(&command.stdout, false) {
+        Ok(stdout) => stdout,
+        Err(err) => {
+            unsafe { close_prepared(&stdin) };
+            return Err(err);
+        }
+    };
+    let stderr = match prepare_stdio(&command.stderr, false) {
+        Ok(stderr) => stderr,
+        Err(err) => {
+            unsafe {
+                close_prepared(&stdin);
+                close_prepared(&stdout);
+            }
+            return Err(err);
+        }
+    };
 
-                assert_eq!(dup2(fd1[0], STDIN_FILENO), STDIN_FILENO);
-                libc::close(fd1[0]);
+    match try_posix_spawn(command, stdin, stdout, stderr) {
+        SpawnAttempt::Done(result) => result,
+        SpawnAttempt::Fallback(stdin, stdout, stderr) => {
+            spawn_fork_exec(command, stdin, stdout, stderr)
+        }
+    }
+}
 
-                assert_eq!(dup2(fd2[1], STDOUT_FILENO), STDOUT_FILENO);
-                libc::close(fd2[1]);
+fn spawn_fork_exec(
+    command: &Command,
+    stdin: PreparedStdio,
+    stdout: PreparedStdio,
+    stderr: PreparedStdio,
+) -> io::Result<Process> {
+    let err_pipe = match unsafe { cloexec_pipe() } {
+        Ok(err_pipe) => err_pipe,
+        Err(err) => {
+            unsafe { close_in_parent(&stdin, &stdout, &stderr) };
+            return Err(err);
+        }
+    };
+
+    unsafe {
+        match fork() {
+            -1 => {
+                let err = io::Error::last_os_error();
+                libc::close(err_pipe[0]);
+                libc::close(err_pipe[1]);
+                close_in_parent(&stdin, &stdout, &stderr);
+                return Err(err);
+            }
+            0 => {
+                libc::close(err_pipe[0]);
 
-                assert_eq!(dup2(fd3[1], STDERR_FILENO), STDERR_FILENO);
-                libc::close(fd3[1]);
+                apply_child_stdio(&stdin, STDIN_FILENO);
+                apply_child_stdio(&stdout, STDOUT_FILENO);
+                apply_child_stdio(&stderr, STDERR_FILENO);
 
-                drop(exec(command));
-                panic!()
+                let err = exec(command);
+                let errno = err.raw_os_error().unwrap_or(0) as i32;
+                let mut payload = [0u8; 8];
+                payload[..4].copy_from_slice(&errno.to_ne_bytes());
+                payload[4..].copy_from_slice(EXEC_FAILURE_SENTINEL);
+                libc::write(err_pipe[1], payload.as_ptr() as *const _, payload.len());
+                libc::_exit(127);
             }
             pid => {
-                libc::close(fd1[0]);
-                libc::close(fd2[1]);
-                libc::close(fd3[1]);
-
-                Ok(Process {
-                    pid,
-                    stdin: File::from_raw_fd(fd1[1]),
-                    stdout: File::from_raw_fd(fd2[0]),
-                    stderr: File::from_raw_fd(fd3[0]),
-                })
+                libc::close(err_pipe[1]);
+                if let Some(fd) = stdin.close_in_parent {
+                    libc::close(fd);
+                }
+                if let Some(fd) = stdout.close_in_parent {
+                    libc::close(fd);
+                }
+                if let Some(fd) = stderr.close_in_parent {
+                    libc::close(fd);
+                }
+
+                let mut err_reader = File::from_raw_fd(err_pipe[0]);
+                let mut payload = [0u8; 8];
+                let mut read = 0;
+                loop {
+                    match err_reader.read(&mut payload[read..]) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            read += n;
+                            if read == payload.len() {
+                                break;
+                            }
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                if read == 0 {
+                    // `execve` succeeded and closed the close-on-exec write end for us.
+                    return Ok(Process {
+                        pid,
+                        stdin: stdin.parent_file,
+                        stdout: stdout.parent_file,
+                        stderr: stderr.parent_file,
+                    });
+                }
+
+                let mut stat = 0;
+                while waitpid(pid, &mut stat, 0) < 0
+                    && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+                {}
+
+                if read != payload.len() || &payload[4..] != EXEC_FAILURE_SENTINEL {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "child sent a short or malformed exec-failure payload",
+                    ));
+                }
+
+                let errno = i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                Err(io::Error::from_raw_os_error(errno))
             }
         }
     }
@@ -106,21 +451,36 @@ pub fn spawn(command: &Command) -> io::Result<Process> {
 #[allow(missing_copy_implementations)]
 pub struct Process {
     pub pid: pid_t,
-    pub stdin: File,
-    pub stdout: File,
-    pub stderr: File,
+    /// Present when `Command::stdin` was `Stdio::Piped`.
+    pub stdin: Option<File>,
+    /// Present when `Command::stdout` was `Stdio::Piped`.
+    pub stdout: Option<File>,
+    /// Present when `Command::stderr` was `Stdio::Piped`.
+    pub stderr: Option<File>,
 }
 
 impl Process {
+    /// Rejects a non-positive `pid`: `0` and negative values are process-group targets to
+    /// `waitpid`/`kill`, not this specific process, and must never reach the raw syscall.
+    fn check_pid(&self) -> io::Result<()> {
+        if self.pid <= 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Process has an invalid pid",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Blocks until this process (and only this process) exits.
     pub fn wait(&self) -> io::Result<ExitStatus> {
+        self.check_pid()?;
         let mut stat = 0;
         loop {
-            let pid = unsafe { waitpid(-1, &mut stat, 0) };
-            if pid < 0 {
-                return Err(io::Error::last_os_error());
-            }
-            if pid == self.pid {
-                break;
+            match unsafe { waitpid(self.pid, &mut stat, 0) } {
+                pid if pid == self.pid => break,
+                _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                _ => return Err(io::Error::last_os_error()),
             }
         }
 
@@ -132,6 +492,38 @@ impl Process {
             }
         }
     }
+
+    /// Checks whether this process has exited, without blocking. Returns `Ok(None)` if it is
+    /// still running.
+    pub fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        self.check_pid()?;
+        let mut stat = 0;
+        loop {
+            match unsafe { waitpid(self.pid, &mut stat, WNOHANG) } {
+                0 => return Ok(None),
+                pid if pid == self.pid => break,
+                _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+
+        unsafe {
+            if WIFEXITED(stat) {
+                Ok(Some(ExitStatus::Code(WEXITSTATUS(stat) as i32)))
+            } else {
+                Ok(Some(ExitStatus::Signal(WTERMSIG(stat) as i32)))
+            }
+        }
+    }
+
+    /// Forcibly terminates this process by sending it `SIGKILL`.
+    pub fn kill(&self) -> io::Result<()> {
+        self.check_pid()?;
+        if unsafe { kill(self.pid, SIGKILL) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
 }
 
 pub enum ExitStatus {
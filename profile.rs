@@ -10,6 +10,8 @@
 
 use platform;
 
+use std::path::PathBuf;
+
 /// A sandbox profile, which specifies the set of operations that this process is allowed to
 /// perform. Operations not in the list are implicitly denied.
 ///
@@ -45,9 +47,9 @@ pub enum Operation {
 #[derive(Clone, Debug)]
 pub enum PathPattern {
     /// One specific path.
-    Literal(Path),
+    Literal(PathBuf),
     /// A directory and all of its contents, recursively.
-    Subpath(Path),
+    Subpath(PathBuf),
 }
 
 /// Describes a network address.
@@ -58,7 +60,7 @@ pub enum AddressPattern {
     /// TCP connections on the given port.
     Tcp(u16),
     /// A local socket at the given path (for example, a Unix socket).
-    LocalSocket(Path),
+    LocalSocket(PathBuf),
 }
 
 impl Profile {
@@ -0,0 +1,139 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The sandboxed command to be spawned.
+
+use platform::process;
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+
+/// A command to run inside a sandbox, analogous to `std::process::Command`.
+///
+/// Paths, arguments, and environment variables are stored as raw bytes rather than `String`, so
+/// that programs whose paths/env contain non-UTF-8 data can still be sandboxed. They are only
+/// converted to `CString` (and checked for embedded NUL bytes) once `spawn` actually builds the
+/// child's `argv`/`envp` — exactly as `std::process::Command` defers that check to spawn time
+/// rather than panicking while the command is still being built up.
+pub struct Command {
+    pub module_path: Vec<u8>,
+    pub args: Vec<Vec<u8>>,
+    pub env: Vec<(Vec<u8>, Vec<u8>)>,
+    pub stdin: Stdio,
+    pub stdout: Stdio,
+    pub stderr: Stdio,
+    /// Whether spawning this command must run a sandbox `Activate` step in the child between
+    /// `fork` and `exec`. Nothing in this tree sets it, so it is always `false`. When it is
+    /// `true`, the Unix `spawn` skips `posix_spawn` (see `can_use_posix_spawn`) and uses
+    /// `fork`/`exec`; that path runs no `Activate` step in the child.
+    pub(crate) requires_child_activation: bool,
+}
+
+/// Describes what a child process's standard stream should be connected to, mirroring
+/// `std::process::Stdio`.
+pub enum Stdio {
+    /// Inherit the corresponding stream from the parent process.
+    Inherit,
+    /// Connect the stream to `/dev/null`.
+    Null,
+    /// Create a pipe, handed back to the caller as `Process::stdin`/`stdout`/`stderr`.
+    Piped,
+    /// Connect the stream to an existing, caller-owned file descriptor.
+    Fd(RawFd),
+    /// Connect the stream to an open file.
+    File(File),
+}
+
+impl Default for Stdio {
+    fn default() -> Stdio {
+        Stdio::Piped
+    }
+}
+
+impl Command {
+    /// Creates a new command that will execute the program at `module_path`.
+    ///
+    /// All three standard streams default to `Stdio::Piped`.
+    pub fn new<T>(module_path: T) -> Command
+    where
+        T: AsRef<OsStr>,
+    {
+        Command {
+            module_path: os_str_to_vec(module_path.as_ref()),
+            args: Vec::new(),
+            env: Vec::new(),
+            stdin: Stdio::default(),
+            stdout: Stdio::default(),
+            stderr: Stdio::default(),
+            requires_child_activation: false,
+        }
+    }
+
+    /// Appends a single argument to the argument list.
+    pub fn arg<T>(&mut self, arg: T) -> &mut Command
+    where
+        T: AsRef<OsStr>,
+    {
+        self.args.push(os_str_to_vec(arg.as_ref()));
+        self
+    }
+
+    /// Appends multiple arguments to the argument list.
+    pub fn args<T>(&mut self, args: &[T]) -> &mut Command
+    where
+        T: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.iter().map(|arg| os_str_to_vec(arg.as_ref())));
+        self
+    }
+
+    /// Inserts or updates an environment variable for the child process.
+    pub fn env<K, V>(&mut self, key: K, value: V) -> &mut Command
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let key = os_str_to_vec(key.as_ref());
+        self.env.retain(|entry| entry.0 != key);
+        self.env.push((key, os_str_to_vec(value.as_ref())));
+        self
+    }
+
+    /// Configures the child's standard input.
+    pub fn stdin(&mut self, stdio: Stdio) -> &mut Command {
+        self.stdin = stdio;
+        self
+    }
+
+    /// Configures the child's standard output.
+    pub fn stdout(&mut self, stdio: Stdio) -> &mut Command {
+        self.stdout = stdio;
+        self
+    }
+
+    /// Configures the child's standard error.
+    pub fn stderr(&mut self, stdio: Stdio) -> &mut Command {
+        self.stderr = stdio;
+        self
+    }
+
+    /// Spawns the command, returning a handle to the running process.
+    pub fn spawn(&self) -> io::Result<process::Process> {
+        process::spawn(self)
+    }
+}
+
+fn os_str_to_vec(s: &OsStr) -> Vec<u8> {
+    s.as_bytes().to_vec()
+}